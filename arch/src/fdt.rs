@@ -2,121 +2,79 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+//! A safe, self-contained serializer for the flattened device tree (FDT/DTB) binary format
+//! that crosvm hands to the guest kernel to describe its hardware. This mirrors the move
+//! cloud-hypervisor/firecracker made away from linking against libfdt: the format is fully
+//! specified, so there is no need for a C dependency or any `unsafe` wrapper around it.
+
 use byteorder::{BigEndian, ByteOrder};
-use libc::{c_char, c_int, c_void};
-use std::error::{self, Error as FdtError};
-use std::ffi::{CStr, CString};
+use std::error;
+use std::ffi::CStr;
 use std::fmt;
-use std::ptr::null;
-
-// This links to libfdt which handles the creation of the binary blob
-// flattened device tree (fdt) that is passed to the kernel and indicates
-// the hardware configuration of the machine.
-#[link(name = "fdt")]
-extern "C" {
-    fn fdt_create(buf: *mut c_void, bufsize: c_int) -> c_int;
-    fn fdt_finish_reservemap(fdt: *mut c_void) -> c_int;
-    fn fdt_begin_node(fdt: *mut c_void, name: *const c_char) -> c_int;
-    fn fdt_property(fdt: *mut c_void, name: *const c_char, val: *const c_void, len: c_int)
-        -> c_int;
-    fn fdt_end_node(fdt: *mut c_void) -> c_int;
-    fn fdt_open_into(fdt: *const c_void, buf: *mut c_void, bufsize: c_int) -> c_int;
-    fn fdt_finish(fdt: *const c_void) -> c_int;
-    fn fdt_pack(fdt: *mut c_void) -> c_int;
-}
 
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// Named error variants, modeled on the libfdt/Android `FdtError` codes, so callers can match on
+// the specific failure instead of treating every error as fatal (in particular, `NoSpace` is
+// recoverable: see the auto-growing writer below).
 #[derive(Debug)]
 pub enum Error {
-    FdtCreateError(c_int),
-    FdtFinishReservemapError(c_int),
-    FdtBeginNodeError(c_int),
-    FdtPropertyError(c_int),
-    FdtEndNodeError(c_int),
-    FdtOpenIntoError(c_int),
-    FdtFinishError(c_int),
-    FdtPackError(c_int),
-    FdtGuestMemoryWriteError,
+    /// The serialized device tree does not fit within the writer's current buffer.
+    NoSpace,
+    /// A token or string offset read from the tree points outside the buffer it indexes into.
+    BadOffset,
+    /// A node path is malformed (e.g. is not absolute).
+    BadPath,
+    /// A `phandle` reference does not resolve to any node in the tree.
+    BadPhandle,
+    /// `begin_node`/`end_node` were called out of sequence: either `end_node` with no node left
+    /// open, or `finish` while a node was still open.
+    BadState,
+    /// A node or property name contains a NUL byte.
+    InvalidString,
+    /// The data passed to `FdtReader::new` does not start with the FDT magic number.
+    InvalidMagic,
+    /// The structure block ended unexpectedly, or a property/node name ran past the end of the
+    /// buffer.
+    Truncated,
+    /// The requested property or node is not present in the tree.
+    NotFound,
+    /// A `phandle` or other unique value was requested to be assigned but is already in use.
+    Exists,
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
         match self {
-            &Error::FdtCreateError(_) => "Error creating FDT",
-            &Error::FdtFinishReservemapError(_) => "Error finishing reserve map",
-            &Error::FdtBeginNodeError(_) => "Error beginning FDT node",
-            &Error::FdtPropertyError(_) => "Error adding FDT property",
-            &Error::FdtEndNodeError(_) => "Error ending FDT node",
-            &Error::FdtOpenIntoError(_) => "Error copying FDT to Guest",
-            &Error::FdtFinishError(_) => "Error performing FDT finish",
-            &Error::FdtPackError(_) => "Error packing FDT",
-            &Error::FdtGuestMemoryWriteError => "Error writing FDT to Guest Memory",
+            &Error::NoSpace => "Serialized FDT exceeds the writer's current buffer size",
+            &Error::BadOffset => "FDT offset points outside the buffer it indexes into",
+            &Error::BadPath => "FDT node path is malformed",
+            &Error::BadPhandle => "FDT phandle does not resolve to any node",
+            &Error::BadState => "begin_node/end_node calls are unbalanced",
+            &Error::InvalidString => "FDT node or property name contains a NUL byte",
+            &Error::InvalidMagic => "Buffer does not start with the FDT magic number",
+            &Error::Truncated => "FDT structure block is truncated or malformed",
+            &Error::NotFound => "Requested FDT property or node does not exist",
+            &Error::Exists => "Requested FDT phandle or value is already in use",
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let prefix = "Libfdt Error: ";
-        match self {
-            &Error::FdtCreateError(fdt_ret)
-            | &Error::FdtFinishReservemapError(fdt_ret)
-            | &Error::FdtBeginNodeError(fdt_ret)
-            | &Error::FdtPropertyError(fdt_ret)
-            | &Error::FdtEndNodeError(fdt_ret)
-            | &Error::FdtOpenIntoError(fdt_ret)
-            | &Error::FdtFinishError(fdt_ret)
-            | &Error::FdtPackError(fdt_ret) => write!(
-                f,
-                "{} {} code: {}",
-                prefix,
-                Error::description(self),
-                fdt_ret
-            ),
-            &Error::FdtGuestMemoryWriteError => {
-                write!(f, "{} {}", prefix, Error::description(self))
-            }
-        }
+        write!(f, "FDT Error: {}", error::Error::description(self))
     }
 }
 
-pub fn begin_node(fdt: &mut Vec<u8>, name: &str) -> Result<(), Box<Error>> {
-    let cstr_name = CString::new(name).unwrap();
-
-    // Safe because we allocated fdt and converted name to a CString
-    let fdt_ret = unsafe { fdt_begin_node(fdt.as_mut_ptr() as *mut c_void, cstr_name.as_ptr()) };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtBeginNodeError(fdt_ret)));
-    }
-    Ok(())
-}
-
-pub fn end_node(fdt: &mut Vec<u8>) -> Result<(), Box<Error>> {
-    // Safe because we allocated fdt
-    let fdt_ret = unsafe { fdt_end_node(fdt.as_mut_ptr() as *mut c_void) };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtEndNodeError(fdt_ret)));
-    }
-    Ok(())
-}
-
-pub fn property(fdt: &mut Vec<u8>, name: &str, val: &[u8]) -> Result<(), Box<Error>> {
-    let cstr_name = CString::new(name).unwrap();
-    let val_ptr = val.as_ptr() as *const c_void;
-
-    // Safe because we allocated fdt and converted name to a CString
-    let fdt_ret = unsafe {
-        fdt_property(
-            fdt.as_mut_ptr() as *mut c_void,
-            cstr_name.as_ptr(),
-            val_ptr,
-            val.len() as i32,
-        )
-    };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtPropertyError(fdt_ret)));
-    }
-    Ok(())
-}
+pub type Result<T> = ::std::result::Result<T, Error>;
 
 fn cpu_to_fdt32(input: u32) -> [u8; 4] {
     let mut buf = [0; 4];
@@ -130,14 +88,6 @@ fn cpu_to_fdt64(input: u64) -> [u8; 8] {
     buf
 }
 
-pub fn property_u32(fdt: &mut Vec<u8>, name: &str, val: u32) -> Result<(), Box<Error>> {
-    property(fdt, name, &cpu_to_fdt32(val))
-}
-
-pub fn property_u64(fdt: &mut Vec<u8>, name: &str, val: u64) -> Result<(), Box<Error>> {
-    property(fdt, name, &cpu_to_fdt64(val))
-}
-
 // Helper to generate a properly formatted byte vector using 32-bit cells
 pub fn generate_prop32(cells: &[u32]) -> Vec<u8> {
     let mut ret: Vec<u8> = Vec::new();
@@ -156,94 +106,915 @@ pub fn generate_prop64(cells: &[u64]) -> Vec<u8> {
     ret
 }
 
-pub fn property_null(fdt: &mut Vec<u8>, name: &str) -> Result<(), Box<Error>> {
-    let cstr_name = CString::new(name).unwrap();
-
-    // Safe because we allocated fdt, converted name to a CString
-    let fdt_ret = unsafe {
-        fdt_property(
-            fdt.as_mut_ptr() as *mut c_void,
-            cstr_name.as_ptr(),
-            null(),
-            0,
-        )
-    };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtPropertyError(fdt_ret)));
-    }
-    Ok(())
-}
-
-pub fn property_cstring(
-    fdt: &mut Vec<u8>,
-    name: &str,
-    cstr_value: &CStr,
-) -> Result<(), Box<Error>> {
-    let value_bytes = cstr_value.to_bytes_with_nul();
-    let cstr_name = CString::new(name).unwrap();
-
-    // Safe because we allocated fdt, converted name and value to CStrings
-    let fdt_ret = unsafe {
-        fdt_property(
-            fdt.as_mut_ptr() as *mut c_void,
-            cstr_name.as_ptr(),
-            value_bytes.as_ptr() as *mut c_void,
-            value_bytes.len() as i32,
-        )
-    };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtPropertyError(fdt_ret)));
-    }
-    Ok(())
-}
-
-pub fn property_string(fdt: &mut Vec<u8>, name: &str, value: &str) -> Result<(), Box<Error>> {
-    let cstr_value = CString::new(value).unwrap();
-    property_cstring(fdt, name, &cstr_value)
-}
-
-pub fn start_fdt(fdt: &mut Vec<u8>, fdt_max_size: usize) -> Result<(), Box<Error>> {
-    // Safe since we allocated this array with fdt_max_size
-    let mut fdt_ret = unsafe { fdt_create(fdt.as_mut_ptr() as *mut c_void, fdt_max_size as c_int) };
-
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtCreateError(fdt_ret)));
-    }
-    // Safe since we allocated this array
-    fdt_ret = unsafe { fdt_finish_reservemap(fdt.as_mut_ptr() as *mut c_void) };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtFinishReservemapError(fdt_ret)));
-    }
-    Ok(())
-}
-
-pub fn finish_fdt(
-    fdt: &mut Vec<u8>,
-    fdt_final: &mut Vec<u8>,
-    fdt_max_size: usize,
-) -> Result<(), Box<Error>> {
-    // Safe since we allocated fdt_final and previously passed in it's size
-    let mut fdt_ret = unsafe { fdt_finish(fdt.as_mut_ptr() as *mut c_void) };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtFinishError(fdt_ret)));
-    }
-
-    // Safe because we allocated both arrays with the correct size
-    fdt_ret = unsafe {
-        fdt_open_into(
-            fdt.as_mut_ptr() as *mut c_void,
-            fdt_final.as_mut_ptr() as *mut c_void,
-            fdt_max_size as i32,
-        )
-    };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtOpenIntoError(fdt_ret)));
-    }
-
-    // Safe since we allocated fdt_final
-    fdt_ret = unsafe { fdt_pack(fdt_final.as_mut_ptr() as *mut c_void) };
-    if fdt_ret != 0 {
-        return Err(Box::new(Error::FdtPackError(fdt_ret)));
-    }
-    Ok(())
-}
\ No newline at end of file
+// Pads `len` up to the next multiple of 4, the alignment the FDT structure block requires
+// between tokens.
+fn pad_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn push_padded(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(bytes);
+    let padded_len = pad_len(buf.len());
+    buf.resize(padded_len, 0);
+}
+
+/// Builds the structure and strings blocks of a flattened device tree and serializes them,
+/// together with the FDT header and an (empty) memory reservation block, into a finished DTB.
+///
+/// # Examples
+///
+/// ```ignore
+/// let mut fdt = FdtWriter::new();
+/// let mut root = fdt.begin_node("")?;
+/// root.property_string("compatible", "linux,dummy-virt")?;
+/// root.begin_node("cpus")?.end()?;
+/// root.end()?;
+/// let dtb = fdt.finish()?;
+/// ```
+pub struct FdtWriter {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: Vec<(String, u32)>,
+    max_size: usize,
+    // Whether `finish` should double `max_size` and retry instead of returning `Error::NoSpace`.
+    auto_grow: bool,
+    node_depth: usize,
+}
+
+// Initial size estimate for `FdtWriter::new`, doubled automatically as needed until the
+// finished tree fits.
+const DEFAULT_MAX_SIZE: usize = 0x10000;
+
+impl FdtWriter {
+    /// Creates a new, empty device tree writer that starts from a reasonable size estimate and
+    /// doubles it automatically if the finished tree turns out to be larger, so callers building
+    /// variable-sized trees don't have to guess a maximum size up front.
+    pub fn new() -> FdtWriter {
+        let mut fdt = FdtWriter::with_max_size(DEFAULT_MAX_SIZE);
+        fdt.auto_grow = true;
+        fdt
+    }
+
+    /// Creates a new, empty device tree writer bounded to `max_size`; `finish` fails with
+    /// `Error::NoSpace` instead of growing past it. Use this when the serialized tree must fit
+    /// a hard upper bound, such as a fixed-size reserved memory region.
+    pub fn with_max_size(max_size: usize) -> FdtWriter {
+        FdtWriter {
+            data: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: Vec::new(),
+            max_size: max_size,
+            auto_grow: false,
+            node_depth: 0,
+        }
+    }
+
+    // Returns the offset of `name` within the strings block, adding it if not already present.
+    fn intern_string(&mut self, name: &str) -> u32 {
+        if let Some(&(_, offset)) = self.string_offsets.iter().find(|&&(ref n, _)| n == name) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.push((name.to_owned(), offset));
+        offset
+    }
+
+    /// Begins a new node named `name` as a child of the currently open node (or as the root
+    /// node, if none is open yet), returning a `NodeGuard` that closes it again on drop (or
+    /// via an explicit call to `NodeGuard::end`), so nodes are balanced by construction.
+    pub fn begin_node<'a>(&'a mut self, name: &str) -> Result<NodeGuard<'a>> {
+        self.begin_node_raw(name)?;
+        Ok(NodeGuard { fdt: Some(self) })
+    }
+
+    fn begin_node_raw(&mut self, name: &str) -> Result<()> {
+        if name.as_bytes().contains(&0) {
+            return Err(Error::InvalidString);
+        }
+        push_padded(&mut self.data, &cpu_to_fdt32(FDT_BEGIN_NODE));
+        let mut name_bytes = name.as_bytes().to_vec();
+        name_bytes.push(0);
+        push_padded(&mut self.data, &name_bytes);
+        self.node_depth += 1;
+        Ok(())
+    }
+
+    fn end_node_raw(&mut self) -> Result<()> {
+        if self.node_depth == 0 {
+            return Err(Error::BadState);
+        }
+        push_padded(&mut self.data, &cpu_to_fdt32(FDT_END_NODE));
+        self.node_depth -= 1;
+        Ok(())
+    }
+
+    /// Adds a property named `name` with the raw value `val` to the currently open node.
+    pub fn property(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        if name.as_bytes().contains(&0) {
+            return Err(Error::InvalidString);
+        }
+        let nameoff = self.intern_string(name);
+        push_padded(&mut self.data, &cpu_to_fdt32(FDT_PROP));
+        push_padded(&mut self.data, &cpu_to_fdt32(val.len() as u32));
+        push_padded(&mut self.data, &cpu_to_fdt32(nameoff));
+        push_padded(&mut self.data, val);
+        Ok(())
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) -> Result<()> {
+        self.property(name, &cpu_to_fdt32(val))
+    }
+
+    pub fn property_u64(&mut self, name: &str, val: u64) -> Result<()> {
+        self.property(name, &cpu_to_fdt64(val))
+    }
+
+    pub fn property_null(&mut self, name: &str) -> Result<()> {
+        self.property(name, &[])
+    }
+
+    pub fn property_cstring(&mut self, name: &str, cstr_value: &CStr) -> Result<()> {
+        self.property(name, cstr_value.to_bytes_with_nul())
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> Result<()> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes)
+    }
+
+    /// Finishes building the tree, failing if a node was left open, and serializes it into a
+    /// complete DTB image. A writer created with `with_max_size` fails with `Error::NoSpace`
+    /// if the tree does not fit; one created with `new` instead doubles its size estimate and
+    /// retries until it does.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.node_depth != 0 {
+            return Err(Error::BadState);
+        }
+        push_padded(&mut self.data, &cpu_to_fdt32(FDT_END));
+
+        // Empty memory reservation block: a single terminating (address, size) zero pair.
+        let mut mem_rsvmap = Vec::new();
+        mem_rsvmap.extend_from_slice(&cpu_to_fdt64(0));
+        mem_rsvmap.extend_from_slice(&cpu_to_fdt64(0));
+
+        let header_size = 10 * 4;
+        let off_mem_rsvmap = header_size as u32;
+        let off_dt_struct = off_mem_rsvmap + mem_rsvmap.len() as u32;
+        let off_dt_strings = off_dt_struct + self.data.len() as u32;
+        let totalsize = off_dt_strings + self.strings.len() as u32;
+
+        while totalsize as usize > self.max_size {
+            if !self.auto_grow {
+                return Err(Error::NoSpace);
+            }
+            self.max_size *= 2;
+        }
+
+        let mut fdt = Vec::with_capacity(totalsize as usize);
+        fdt.extend_from_slice(&cpu_to_fdt32(FDT_MAGIC));
+        fdt.extend_from_slice(&cpu_to_fdt32(totalsize));
+        fdt.extend_from_slice(&cpu_to_fdt32(off_dt_struct));
+        fdt.extend_from_slice(&cpu_to_fdt32(off_dt_strings));
+        fdt.extend_from_slice(&cpu_to_fdt32(off_mem_rsvmap));
+        fdt.extend_from_slice(&cpu_to_fdt32(FDT_VERSION));
+        fdt.extend_from_slice(&cpu_to_fdt32(FDT_LAST_COMP_VERSION));
+        fdt.extend_from_slice(&cpu_to_fdt32(0)); // boot_cpuid_phys
+        fdt.extend_from_slice(&cpu_to_fdt32(self.strings.len() as u32));
+        fdt.extend_from_slice(&cpu_to_fdt32(self.data.len() as u32));
+        fdt.extend_from_slice(&mem_rsvmap);
+        fdt.extend_from_slice(&self.data);
+        fdt.extend_from_slice(&self.strings);
+
+        Ok(fdt)
+    }
+}
+
+/// An open FDT node, returned by `FdtWriter::begin_node`. Closes the node when dropped, or
+/// when `end` is called explicitly, so `begin_node`/`end_node` calls can never be mismatched.
+/// Properties and child nodes are added through the guard itself rather than the `FdtWriter`
+/// it borrows, so the borrow checker enforces that a node's children are finished before its
+/// parent can be touched again.
+pub struct NodeGuard<'a> {
+    // `None` only right after `end` has taken ownership of the writer; always `Some` otherwise.
+    fdt: Option<&'a mut FdtWriter>,
+}
+
+impl<'a> NodeGuard<'a> {
+    fn fdt(&mut self) -> &mut FdtWriter {
+        self.fdt.as_mut().unwrap()
+    }
+
+    /// Begins a child node, as `FdtWriter::begin_node`.
+    pub fn begin_node<'b>(&'b mut self, name: &str) -> Result<NodeGuard<'b>> {
+        self.fdt().begin_node(name)
+    }
+
+    /// Adds a property, as `FdtWriter::property`.
+    pub fn property(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        self.fdt().property(name, val)
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) -> Result<()> {
+        self.fdt().property_u32(name, val)
+    }
+
+    pub fn property_u64(&mut self, name: &str, val: u64) -> Result<()> {
+        self.fdt().property_u64(name, val)
+    }
+
+    pub fn property_null(&mut self, name: &str) -> Result<()> {
+        self.fdt().property_null(name)
+    }
+
+    pub fn property_cstring(&mut self, name: &str, cstr_value: &CStr) -> Result<()> {
+        self.fdt().property_cstring(name, cstr_value)
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> Result<()> {
+        self.fdt().property_string(name, value)
+    }
+
+    /// Explicitly closes the node, returning any error from the matching end token instead of
+    /// silently discarding it as `Drop` would.
+    pub fn end(mut self) -> Result<()> {
+        self.fdt.take().unwrap().end_node_raw()
+    }
+}
+
+impl<'a> Drop for NodeGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(fdt) = self.fdt.take() {
+            // Best-effort: there is no way to propagate an error out of a destructor. Callers
+            // that care about the result should call `end()` explicitly instead.
+            let _ = fdt.end_node_raw();
+        }
+    }
+}
+
+// Default #address-cells/#size-cells for a node that does not specify its own, per the DTB
+// spec (also used for the implicit root node).
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+fn read_u32_at(data: &[u8], off: usize) -> Result<u32> {
+    if off + 4 > data.len() {
+        return Err(Error::Truncated);
+    }
+    Ok(BigEndian::read_u32(&data[off..off + 4]))
+}
+
+// Reads a NUL-terminated string starting at `off`, returning it and the offset of the byte
+// following its 4-byte-aligned padding.
+fn read_name(data: &[u8], off: usize) -> Result<(&str, usize)> {
+    let end = data[off..]
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::Truncated)?;
+    let name = ::std::str::from_utf8(&data[off..off + end]).map_err(|_| Error::InvalidString)?;
+    Ok((name, pad_len(off + end + 1)))
+}
+
+fn cells_to_u64(data: &[u8], num_cells: u32) -> u64 {
+    match num_cells {
+        1 => BigEndian::read_u32(data) as u64,
+        2 => BigEndian::read_u64(data),
+        _ => 0,
+    }
+}
+
+/// A read-only view over a flattened device tree, letting callers validate a tree they
+/// generated, extract the memory layout the kernel will see, or merge fragments.
+#[derive(Debug)]
+pub struct FdtReader<'a> {
+    data: &'a [u8],
+    off_dt_struct: usize,
+    off_dt_strings: usize,
+}
+
+impl<'a> FdtReader<'a> {
+    /// Wraps an existing, fully serialized DTB image for reading.
+    pub fn new(data: &'a [u8]) -> Result<FdtReader<'a>> {
+        if data.len() < 40 || read_u32_at(data, 0)? != FDT_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let off_dt_struct = read_u32_at(data, 8)? as usize;
+        let off_dt_strings = read_u32_at(data, 12)? as usize;
+        if off_dt_struct >= data.len() || off_dt_strings > data.len() {
+            return Err(Error::BadOffset);
+        }
+        Ok(FdtReader {
+            data: data,
+            off_dt_struct: off_dt_struct,
+            off_dt_strings: off_dt_strings,
+        })
+    }
+
+    /// Returns the root node of the tree.
+    pub fn root(&'a self) -> Result<FdtNode<'a>> {
+        if read_u32_at(self.data, self.off_dt_struct)? != FDT_BEGIN_NODE {
+            return Err(Error::Truncated);
+        }
+        let (_, off) = read_name(self.data, self.off_dt_struct + 4)?;
+        Ok(FdtNode {
+            reader: self,
+            name: "",
+            offset: off,
+            reg_address_cells: DEFAULT_ADDRESS_CELLS,
+            reg_size_cells: DEFAULT_SIZE_CELLS,
+        })
+    }
+
+    /// Looks up a node by an absolute, `/`-separated path such as `/cpus/cpu@0`.
+    pub fn get_node(&'a self, path: &str) -> Result<Option<FdtNode<'a>>> {
+        if !path.is_empty() && !path.starts_with('/') {
+            return Err(Error::BadPath);
+        }
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            match node.children()?.find(|child| child.name() == component) {
+                Some(child) => node = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(node))
+    }
+
+    fn string_at(&self, offset: usize) -> Result<&'a str> {
+        if self.off_dt_strings + offset >= self.data.len() {
+            return Err(Error::BadOffset);
+        }
+        let (name, _) = read_name(self.data, self.off_dt_strings + offset)?;
+        Ok(name)
+    }
+}
+
+/// A node within an `FdtReader`'s tree: a name, a set of properties and a set of child nodes.
+#[derive(Debug)]
+pub struct FdtNode<'a> {
+    reader: &'a FdtReader<'a>,
+    name: &'a str,
+    // Offset of the first token following this node's name (its properties and children).
+    offset: usize,
+    // #address-cells/#size-cells inherited from the parent, used to decode this node's own
+    // `reg` property.
+    reg_address_cells: u32,
+    reg_size_cells: u32,
+}
+
+impl<'a> FdtNode<'a> {
+    /// This node's name, including its unit address if it has one (e.g. `cpu@0`).
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Iterates over this node's own properties as `(name, value)` pairs.
+    pub fn properties(&self) -> Result<impl Iterator<Item = (&'a str, &'a [u8])>> {
+        Ok(self.walk()?.0.into_iter())
+    }
+
+    /// Iterates over this node's immediate child nodes.
+    pub fn children(&self) -> Result<impl Iterator<Item = FdtNode<'a>>> {
+        Ok(self.walk()?.1.into_iter())
+    }
+
+    /// Returns the raw value of property `name`, if present.
+    pub fn property(&self, name: &str) -> Result<Option<&'a [u8]>> {
+        for (prop_name, value) in self.walk()?.0 {
+            if prop_name == name {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// This node's own `#address-cells`, defaulting to 2 if unset; applies to the `reg` of its
+    /// children.
+    pub fn address_cells(&self) -> Result<u32> {
+        Ok(self.own_cells()?.0)
+    }
+
+    /// This node's own `#size-cells`, defaulting to 1 if unset; applies to the `reg` of its
+    /// children.
+    pub fn size_cells(&self) -> Result<u32> {
+        Ok(self.own_cells()?.1)
+    }
+
+    // Scans this node's own immediate properties for `#address-cells`/`#size-cells`, skipping
+    // over (without recursing into) any child nodes. This must not go through `walk`, which
+    // itself needs a child's inherited cell counts to build that child's `FdtNode` -- computing
+    // them by calling back into `address_cells`/`size_cells` on `self` would recurse forever.
+    fn own_cells(&self) -> Result<(u32, u32)> {
+        let data = self.reader.data;
+        let mut off = self.offset;
+        let mut address_cells = DEFAULT_ADDRESS_CELLS;
+        let mut size_cells = DEFAULT_SIZE_CELLS;
+        loop {
+            let token = read_u32_at(data, off)?;
+            off += 4;
+            match token {
+                FDT_NOP => continue,
+                FDT_PROP => {
+                    let len = read_u32_at(data, off)? as usize;
+                    off += 4;
+                    let nameoff = read_u32_at(data, off)? as usize;
+                    off += 4;
+                    if off + len > data.len() {
+                        return Err(Error::Truncated);
+                    }
+                    let value = &data[off..off + len];
+                    off = pad_len(off + len);
+                    match (self.reader.string_at(nameoff)?, len) {
+                        ("#address-cells", 4) => address_cells = BigEndian::read_u32(value),
+                        ("#size-cells", 4) => size_cells = BigEndian::read_u32(value),
+                        _ => {}
+                    }
+                }
+                FDT_BEGIN_NODE => {
+                    let (_, next_off) = read_name(data, off)?;
+                    off = skip_node_body(data, next_off)?;
+                }
+                FDT_END_NODE | FDT_END => break,
+                _ => return Err(Error::Truncated),
+            }
+        }
+        Ok((address_cells, size_cells))
+    }
+
+    /// Decodes this node's `reg` property into `(address, size)` pairs, using the parent's
+    /// `#address-cells`/`#size-cells`.
+    pub fn reg(&self) -> Result<Vec<(u64, u64)>> {
+        let value = match self.property("reg")? {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+        let entry_len = 4 * (self.reg_address_cells + self.reg_size_cells) as usize;
+        if entry_len == 0 || value.len() % entry_len != 0 {
+            return Err(Error::Truncated);
+        }
+        let addr_bytes = 4 * self.reg_address_cells as usize;
+        Ok(value.chunks(entry_len)
+            .map(|entry| {
+                (cells_to_u64(&entry[..addr_bytes], self.reg_address_cells),
+                 cells_to_u64(&entry[addr_bytes..], self.reg_size_cells))
+            })
+            .collect())
+    }
+
+    /// Decodes this node's `ranges` property into `(child_address, parent_address, size)`
+    /// triples, mapping this node's own address space onto its parent's.
+    pub fn ranges(&self) -> Result<Vec<(u64, u64, u64)>> {
+        let value = match self.property("ranges")? {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+        let child_acells = self.address_cells()?;
+        let size_cells = self.size_cells()?;
+        let entry_len = 4 * (child_acells + self.reg_address_cells + size_cells) as usize;
+        if entry_len == 0 || value.len() % entry_len != 0 {
+            return Err(Error::Truncated);
+        }
+        let child_bytes = 4 * child_acells as usize;
+        let parent_bytes = 4 * self.reg_address_cells as usize;
+        Ok(value.chunks(entry_len)
+            .map(|entry| {
+                let (child, rest) = entry.split_at(child_bytes);
+                let (parent, size) = rest.split_at(parent_bytes);
+                (cells_to_u64(child, child_acells),
+                 cells_to_u64(parent, self.reg_address_cells),
+                 cells_to_u64(size, size_cells))
+            })
+            .collect())
+    }
+
+    /// The NUL-separated strings of this node's `compatible` property, if any.
+    pub fn compatible(&self) -> Result<Vec<&'a str>> {
+        let value = match self.property("compatible")? {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+        value.split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| ::std::str::from_utf8(s).map_err(|_| Error::InvalidString))
+            .collect()
+    }
+
+    // Walks this node's properties and immediate children in a single pass over the structure
+    // block, stopping at the matching FDT_END_NODE.
+    fn walk(&self) -> Result<(Vec<(&'a str, &'a [u8])>, Vec<FdtNode<'a>>)> {
+        let data = self.reader.data;
+        let mut off = self.offset;
+        let mut props = Vec::new();
+        let mut children = Vec::new();
+        // Computed once up front via a non-recursive scan: children inherit this node's own
+        // cell counts, but building a child's `FdtNode` happens inside this same walk, so this
+        // cannot be computed lazily through `address_cells`/`size_cells` without recursing back
+        // into `walk` for every nested level.
+        let (own_address_cells, own_size_cells) = self.own_cells()?;
+        loop {
+            let token = read_u32_at(data, off)?;
+            off += 4;
+            match token {
+                FDT_NOP => continue,
+                FDT_PROP => {
+                    let len = read_u32_at(data, off)? as usize;
+                    off += 4;
+                    let nameoff = read_u32_at(data, off)? as usize;
+                    off += 4;
+                    if off + len > data.len() {
+                        return Err(Error::Truncated);
+                    }
+                    let value = &data[off..off + len];
+                    off = pad_len(off + len);
+                    props.push((self.reader.string_at(nameoff)?, value));
+                }
+                FDT_BEGIN_NODE => {
+                    let (name, next_off) = read_name(data, off)?;
+                    let child = FdtNode {
+                        reader: self.reader,
+                        name: name,
+                        offset: next_off,
+                        reg_address_cells: own_address_cells,
+                        reg_size_cells: own_size_cells,
+                    };
+                    off = child.skip()?;
+                    children.push(child);
+                }
+                FDT_END_NODE | FDT_END => break,
+                _ => return Err(Error::Truncated),
+            }
+        }
+        Ok((props, children))
+    }
+
+    // Skips over this node's properties, children and its own FDT_END_NODE, returning the
+    // offset of the token following it.
+    fn skip(&self) -> Result<usize> {
+        skip_node_body(self.reader.data, self.offset)
+    }
+}
+
+// Skips a node body (properties and nested children) starting right after the node's name,
+// returning the offset of the token following its matching `FDT_END_NODE`. Does not inspect any
+// property value, so it can be used to jump over a child before that child's own
+// `#address-cells`/`#size-cells` are known.
+fn skip_node_body(data: &[u8], off: usize) -> Result<usize> {
+    let mut off = off;
+    let mut depth = 0;
+    loop {
+        let token = read_u32_at(data, off)?;
+        off += 4;
+        match token {
+            FDT_NOP => continue,
+            FDT_PROP => {
+                let len = read_u32_at(data, off)? as usize;
+                off = pad_len(off + 8 + len);
+            }
+            FDT_BEGIN_NODE => {
+                let (_, next_off) = read_name(data, off)?;
+                off = next_off;
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return Ok(off);
+                }
+                depth -= 1;
+            }
+            _ => return Err(Error::Truncated),
+        }
+    }
+}
+
+/// Interrupt is wired to a Shared Peripheral Interrupt, per the GICv3 binding.
+pub const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+/// Interrupt is wired to a Private Peripheral Interrupt, per the GICv3 binding.
+pub const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+
+/// Interrupt is edge-triggered, rising edge.
+pub const IRQ_TYPE_EDGE_RISING: u32 = 1;
+/// Interrupt is level-triggered, active high.
+pub const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// Builds an `interrupts` property entry of the form `[type, number, flags]`, as consumed by the
+/// GICv3 interrupt binding (`#interrupt-cells = <3>`).
+pub fn generate_irq_prop(irq_type: u32, irq_number: u32, flags: u32) -> Vec<u8> {
+    generate_prop32(&[irq_type, irq_number, flags])
+}
+
+/// Hands out unique, non-zero `phandle` values for cross-referencing nodes (e.g. a GPIO
+/// controller's `interrupt-parent` pointing back at the GIC). Phandle 0 is reserved by the DTB
+/// spec to mean "no phandle", so allocation starts at 1.
+pub struct PhandleAllocator {
+    next: u32,
+}
+
+impl PhandleAllocator {
+    pub fn new() -> PhandleAllocator {
+        PhandleAllocator { next: 1 }
+    }
+
+    /// Returns a phandle value not previously returned by this allocator.
+    pub fn alloc(&mut self) -> u32 {
+        let phandle = self.next;
+        self.next += 1;
+        phandle
+    }
+}
+
+/// Writes an aarch64 GICv3 `interrupt-controller` node as a child of `parent`, with `dist` and
+/// `redist` as the `(address, size)` of the distributor and redistributor regions, and returns
+/// its allocated phandle so other nodes can reference it as their `interrupt-parent`.
+pub fn write_gic_node<'a>(parent: &mut NodeGuard<'a>, phandles: &mut PhandleAllocator,
+                           dist: (u64, u64), redist: (u64, u64))
+                           -> Result<u32> {
+    let phandle = phandles.alloc();
+    let mut gic = parent.begin_node("intc")?;
+    gic.property_string("compatible", "arm,gic-v3")?;
+    gic.property_u32("#interrupt-cells", 3)?;
+    gic.property_null("interrupt-controller")?;
+    gic.property("reg", &generate_prop64(&[dist.0, dist.1, redist.0, redist.1]))?;
+    gic.property_u32("phandle", phandle)?;
+    gic.end()?;
+    Ok(phandle)
+}
+
+/// Writes an aarch64 `msi-controller` (GICv3 ITS) node as a child of `parent`, with `reg` as its
+/// `(address, size)`, and returns its allocated phandle.
+pub fn write_msi_controller_node<'a>(parent: &mut NodeGuard<'a>, phandles: &mut PhandleAllocator,
+                                      reg: (u64, u64))
+                                      -> Result<u32> {
+    let phandle = phandles.alloc();
+    let mut msi = parent.begin_node("msi-controller")?;
+    msi.property_string("compatible", "arm,gic-v3-its")?;
+    msi.property_null("msi-controller")?;
+    msi.property("reg", &generate_prop64(&[reg.0, reg.1]))?;
+    msi.property_u32("phandle", phandle)?;
+    msi.end()?;
+    Ok(phandle)
+}
+
+/// Writes a fixed-rate `clock` node named `name` as a child of `parent`, running at
+/// `freq_hz`, and returns its allocated phandle.
+pub fn write_clock_node<'a>(parent: &mut NodeGuard<'a>, phandles: &mut PhandleAllocator,
+                             name: &str, freq_hz: u32)
+                             -> Result<u32> {
+    let phandle = phandles.alloc();
+    let mut clock = parent.begin_node(name)?;
+    clock.property_string("compatible", "fixed-clock")?;
+    clock.property_u32("#clock-cells", 0)?;
+    clock.property_u32("clock-frequency", freq_hz)?;
+    clock.property_u32("phandle", phandle)?;
+    clock.end()?;
+    Ok(phandle)
+}
+
+/// Writes a PL061 `gpio` controller node as a child of `parent`, with `reg` as its
+/// `(address, size)`, wired to `irq_number` as a Shared Peripheral Interrupt on the GIC
+/// identified by `gic_phandle`, and returns its allocated phandle.
+pub fn write_gpio_node<'a>(parent: &mut NodeGuard<'a>, phandles: &mut PhandleAllocator,
+                            reg: (u64, u64), gic_phandle: u32, irq_number: u32)
+                            -> Result<u32> {
+    let phandle = phandles.alloc();
+    let mut gpio = parent.begin_node("gpio")?;
+    gpio.property_string("compatible", "arm,pl061")?;
+    gpio.property("reg", &generate_prop64(&[reg.0, reg.1]))?;
+    gpio.property_null("gpio-controller")?;
+    gpio.property_u32("#gpio-cells", 2)?;
+    gpio.property_u32("interrupt-parent", gic_phandle)?;
+    gpio.property("interrupts",
+                  &generate_irq_prop(GIC_FDT_IRQ_TYPE_SPI, irq_number, IRQ_TYPE_LEVEL_HIGH))?;
+    gpio.property_u32("phandle", phandle)?;
+    gpio.end()?;
+    Ok(phandle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_tree() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap().end().unwrap();
+        let dtb = fdt.finish().unwrap();
+        assert_eq!(BigEndian::read_u32(&dtb[0..4]), FDT_MAGIC);
+    }
+
+    #[test]
+    fn drop_closes_node() {
+        let mut fdt = FdtWriter::new();
+        {
+            let _cpus = fdt.begin_node("cpus").unwrap();
+        }
+        let dtb = fdt.finish().unwrap();
+        assert_eq!(BigEndian::read_u32(&dtb[0..4]), FDT_MAGIC);
+    }
+
+    #[test]
+    fn properties_and_nesting() {
+        let mut fdt = FdtWriter::new();
+        let mut root = fdt.begin_node("").unwrap();
+        root.property_string("compatible", "linux,dummy-virt").unwrap();
+        root.property_u32("#address-cells", 2).unwrap();
+        let mut cpus = root.begin_node("cpus").unwrap();
+        cpus.property_u64("reg", 0).unwrap();
+        cpus.end().unwrap();
+        root.end().unwrap();
+        let dtb = fdt.finish().unwrap();
+        assert_eq!(BigEndian::read_u32(&dtb[0..4]), FDT_MAGIC);
+    }
+
+    #[test]
+    fn total_size_too_large() {
+        let mut fdt = FdtWriter::with_max_size(16);
+        fdt.begin_node("").unwrap().end().unwrap();
+        match fdt.finish() {
+            Err(Error::NoSpace) => {}
+            other => panic!("expected NoSpace, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn auto_grow_past_initial_size() {
+        // Force the writer's starting estimate far below what this tree needs, so `finish`
+        // only succeeds if it actually doubles `max_size` and retries rather than failing the
+        // first time the tree doesn't fit.
+        let mut fdt = FdtWriter::with_max_size(16);
+        fdt.auto_grow = true;
+        let mut root = fdt.begin_node("").unwrap();
+        for i in 0..8 {
+            root.begin_node(&format!("node@{}", i)).unwrap().end().unwrap();
+        }
+        root.end().unwrap();
+        let dtb = fdt.finish().unwrap();
+        assert_eq!(BigEndian::read_u32(&dtb[0..4]), FDT_MAGIC);
+        assert!(dtb.len() > 16);
+    }
+
+    fn build_test_tree() -> Vec<u8> {
+        let mut fdt = FdtWriter::new();
+        let mut root = fdt.begin_node("").unwrap();
+        root.property_u32("#address-cells", 2).unwrap();
+        root.property_u32("#size-cells", 1).unwrap();
+        root.property_string("compatible", "linux,dummy-virt").unwrap();
+        let mut cpus = root.begin_node("cpus").unwrap();
+        cpus.property_u32("#address-cells", 1).unwrap();
+        cpus.property_u32("#size-cells", 0).unwrap();
+        let mut cpu0 = cpus.begin_node("cpu@0").unwrap();
+        cpu0.property_string("compatible", "arm,arm-v8").unwrap();
+        cpu0.property_u32("reg", 0).unwrap();
+        cpu0.end().unwrap();
+        cpus.end().unwrap();
+        let mut memory = root.begin_node("memory@80000000").unwrap();
+        memory.property_string("device_type", "memory").unwrap();
+        // Two address cells (high, low) followed by one size cell, per the root's
+        // #address-cells=2 / #size-cells=1.
+        memory.property("reg", &generate_prop32(&[0, 0x8000_0000, 0x4000_0000])).unwrap();
+        memory.end().unwrap();
+        root.end().unwrap();
+        fdt.finish().unwrap()
+    }
+
+    #[test]
+    fn read_root_properties() {
+        let dtb = build_test_tree();
+        let reader = FdtReader::new(&dtb).unwrap();
+        let root = reader.root().unwrap();
+        assert_eq!(root.compatible().unwrap(), vec!["linux,dummy-virt"]);
+        assert_eq!(root.address_cells().unwrap(), 2);
+        assert_eq!(root.size_cells().unwrap(), 1);
+    }
+
+    #[test]
+    fn read_children() {
+        let dtb = build_test_tree();
+        let reader = FdtReader::new(&dtb).unwrap();
+        let root = reader.root().unwrap();
+        let names: Vec<&str> = root.children().unwrap().map(|n| n.name()).collect();
+        assert_eq!(names, vec!["cpus", "memory@80000000"]);
+    }
+
+    #[test]
+    fn read_reg() {
+        let dtb = build_test_tree();
+        let reader = FdtReader::new(&dtb).unwrap();
+        let memory = reader.get_node("/memory@80000000").unwrap().unwrap();
+        assert_eq!(memory.reg().unwrap(), vec![(0x8000_0000, 0x4000_0000)]);
+    }
+
+    #[test]
+    fn read_nested_path() {
+        let dtb = build_test_tree();
+        let reader = FdtReader::new(&dtb).unwrap();
+        let cpu0 = reader.get_node("/cpus/cpu@0").unwrap().unwrap();
+        assert_eq!(cpu0.compatible().unwrap(), vec!["arm,arm-v8"]);
+        assert!(reader.get_node("/cpus/cpu@1").unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_magic() {
+        match FdtReader::new(&[0u8; 64]) {
+            Err(Error::InvalidMagic) => {}
+            other => panic!("expected InvalidMagic, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_state() {
+        // Bypass `NodeGuard` to call `finish` directly while a node is still open; the guard
+        // itself makes this unreachable through the public API.
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node_raw("").unwrap();
+        match fdt.finish() {
+            Err(Error::BadState) => {}
+            other => panic!("expected BadState, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_nodes_with_nested_children_and_siblings() {
+        // Regression test for a recursion bug in `FdtNode::walk`: reading back a node that has
+        // at least one child used to recurse forever while computing that child's inherited
+        // `#address-cells`/`#size-cells`. Build a tree with two levels of nesting and siblings
+        // at both levels, then walk every node in it.
+        let mut fdt = FdtWriter::new();
+        let mut root = fdt.begin_node("").unwrap();
+        root.property_u32("#address-cells", 1).unwrap();
+        root.property_u32("#size-cells", 1).unwrap();
+        let mut bus0 = root.begin_node("bus@0").unwrap();
+        bus0.property_u32("#address-cells", 1).unwrap();
+        bus0.property_u32("#size-cells", 1).unwrap();
+        bus0.begin_node("dev@0").unwrap().end().unwrap();
+        bus0.begin_node("dev@1").unwrap().end().unwrap();
+        bus0.end().unwrap();
+        root.begin_node("bus@1").unwrap().end().unwrap();
+        root.end().unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let reader = FdtReader::new(&dtb).unwrap();
+        let root = reader.root().unwrap();
+        let bus_names: Vec<&str> = root.children().unwrap().map(|n| n.name()).collect();
+        assert_eq!(bus_names, vec!["bus@0", "bus@1"]);
+
+        let bus0 = reader.get_node("/bus@0").unwrap().unwrap();
+        let dev_names: Vec<&str> = bus0.children().unwrap().map(|n| n.name()).collect();
+        assert_eq!(dev_names, vec!["dev@0", "dev@1"]);
+    }
+
+    #[test]
+    fn bad_path() {
+        let dtb = build_test_tree();
+        let reader = FdtReader::new(&dtb).unwrap();
+        match reader.get_node("cpus") {
+            Err(Error::BadPath) => {}
+            other => panic!("expected BadPath, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn phandle_allocator_yields_unique_nonzero_values() {
+        let mut phandles = PhandleAllocator::new();
+        let a = phandles.alloc();
+        let b = phandles.alloc();
+        assert_ne!(a, 0);
+        assert_ne!(b, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn generate_irq_prop_encodes_type_number_flags() {
+        let prop = generate_irq_prop(GIC_FDT_IRQ_TYPE_SPI, 42, IRQ_TYPE_LEVEL_HIGH);
+        assert_eq!(prop, generate_prop32(&[GIC_FDT_IRQ_TYPE_SPI, 42, IRQ_TYPE_LEVEL_HIGH]));
+    }
+
+    #[test]
+    fn aarch64_device_nodes_cross_reference_phandles() {
+        let mut fdt = FdtWriter::new();
+        let mut phandles = PhandleAllocator::new();
+        let mut root = fdt.begin_node("").unwrap();
+        root.property_u32("#address-cells", 2).unwrap();
+        root.property_u32("#size-cells", 2).unwrap();
+        let gic_phandle = write_gic_node(&mut root, &mut phandles,
+                                          (0x8000_0000, 0x1_0000),
+                                          (0x8001_0000, 0x10_0000))
+            .unwrap();
+        let gpio_phandle = write_gpio_node(&mut root, &mut phandles, (0x9000_0000, 0x1000),
+                                            gic_phandle, 7)
+            .unwrap();
+        write_msi_controller_node(&mut root, &mut phandles, (0x8080_0000, 0x2_0000)).unwrap();
+        write_clock_node(&mut root, &mut phandles, "apb-pclk", 24_000_000).unwrap();
+        root.end().unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let reader = FdtReader::new(&dtb).unwrap();
+        let gpio = reader.get_node("/gpio").unwrap().unwrap();
+        assert_ne!(gic_phandle, gpio_phandle);
+        assert_eq!(gpio.property("phandle").unwrap().unwrap(),
+                   &generate_prop32(&[gpio_phandle])[..]);
+        assert_eq!(gpio.property("interrupt-parent").unwrap().unwrap(),
+                   &generate_prop32(&[gic_phandle])[..]);
+    }
+}
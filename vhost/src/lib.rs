@@ -7,8 +7,21 @@ extern crate net_util;
 extern crate sys_util;
 extern crate virtio_sys;
 
+mod log;
+pub use log::{DirtyLog, VHOST_LOG_PAGE};
 mod net;
-pub use net::Net;
+pub use net::{MultiQueueNet, Net, VIRTIO_NET_F_MQ};
+mod vsock;
+pub use vsock::Vsock;
+mod vhost_user;
+pub use vhost_user::Master;
+
+/// Feature bit enabling dirty-page logging of guest memory writes, for use with
+/// `Vhost::set_log_base`.
+pub const VHOST_F_LOG_ALL: u64 = 1 << 26;
+/// Per-vring flag, set in `set_vring_addr`'s `flags` argument, enabling logging for that vring
+/// once `VHOST_F_LOG_ALL` has been negotiated.
+pub const VHOST_VRING_F_LOG: u32 = 1 << 0;
 
 use std::mem;
 use std::os::unix::io::AsRawFd;
@@ -33,9 +46,23 @@ pub enum Error {
     AvailAddress(GuestMemoryError),
     /// Invalid log address.
     LogAddress(GuestMemoryError),
+    /// Error connecting to a vhost-user slave's socket.
+    VhostUserConnect(std::io::Error),
+    /// Error sending or receiving a vhost-user message.
+    VhostUserProtocol(std::io::Error),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Memory layout of a virtqueue, as negotiated between driver and device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RingLayout {
+    /// The traditional layout: a separate descriptor table, available ring and used ring.
+    Split,
+    /// The layout negotiated via `VIRTIO_F_RING_PACKED`: a single descriptor ring plus a
+    /// driver and a device event-suppression structure.
+    Packed,
+}
+
 fn ioctl_result<T>() -> Result<T> {
     Err(Error::IoctlError(SysError::last()))
 }
@@ -159,20 +186,38 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
 
     // TODO(smbarber): This is copypasta. Eliminate the copypasta.
     fn is_valid(&self,
+                ring_layout: RingLayout,
                 queue_max_size: u16,
                 queue_size: u16,
                 desc_addr: GuestAddress,
                 avail_addr: GuestAddress,
                 used_addr: GuestAddress) -> bool {
-        let desc_table_size = 16 * queue_size as usize;
-        let avail_ring_size = 6 + 2 * queue_size as usize;
-        let used_ring_size = 6 + 8 * queue_size as usize;
-        if queue_size > queue_max_size || queue_size == 0 ||
-                  (queue_size & (queue_size - 1)) != 0 {
-            false
-        } else if desc_addr
-                      .checked_add(desc_table_size)
-                      .map_or(true, |v| !self.mem().address_in_range(v)) {
+        if queue_size > queue_max_size || queue_size == 0 {
+            return false;
+        }
+
+        // For a split ring, `avail_addr`/`used_addr` are the available/used rings; for a
+        // packed ring they are instead the driver/device event-suppression structures, each
+        // a single 4-byte descriptor.
+        //
+        // Only the split layout requires a power-of-two queue size: its available/used ring
+        // indices wrap via a bitmask. The packed layout's wrap bit doesn't depend on the queue
+        // size being a power of two, so the virtio spec allows any non-zero size up to the
+        // negotiated maximum.
+        let (desc_table_size, avail_ring_size, used_ring_size) = match ring_layout {
+            RingLayout::Split => {
+                if (queue_size & (queue_size - 1)) != 0 {
+                    return false;
+                }
+                (16 * queue_size as usize,
+                 6 + 2 * queue_size as usize,
+                 6 + 8 * queue_size as usize)
+            }
+            RingLayout::Packed => (16 * queue_size as usize, 4, 4),
+        };
+        if desc_addr
+                .checked_add(desc_table_size)
+                .map_or(true, |v| !self.mem().address_in_range(v)) {
             false
         } else if avail_addr
                       .checked_add(avail_ring_size)
@@ -191,15 +236,17 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
     /// Set the addresses for a given vring.
     ///
     /// # Arguments
+    /// * `ring_layout` - Split or packed virtqueue layout negotiated with the driver.
     /// * `queue_max_size` - Maximum queue size supported by the device.
     /// * `queue_size` - Actual queue size negotiated by the driver.
     /// * `queue_index` - Index of the queue to set addresses for.
     /// * `flags` - Bitmask of vring flags.
-    /// * `desc_addr` - Descriptor table address.
-    /// * `used_addr` - Used ring buffer address.
-    /// * `avail_addr` - Available ring buffer address.
+    /// * `desc_addr` - Descriptor table (or packed descriptor ring) address.
+    /// * `used_addr` - Used ring (or device event-suppression structure) address.
+    /// * `avail_addr` - Available ring (or driver event-suppression structure) address.
     /// * `log_addr` - Optional address for logging.
     fn set_vring_addr(&self,
+                      ring_layout: RingLayout,
                       queue_max_size: u16,
                       queue_size: u16,
                       queue_index: usize,
@@ -211,7 +258,7 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
                       -> Result<()> {
         // TODO(smbarber): Refactor out virtio from crosvm so we can
         // validate a Queue struct directly.
-        if !self.is_valid(queue_max_size, queue_size, desc_addr, used_addr, avail_addr) {
+        if !self.is_valid(ring_layout, queue_max_size, queue_size, desc_addr, avail_addr, used_addr) {
             return Err(Error::InvalidQueue);
         }
 
@@ -279,6 +326,26 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
         Ok(())
     }
 
+    /// Set the base address of the dirty-page log bitmap that the kernel marks while
+    /// `VHOST_F_LOG_ALL` is negotiated and a vring's `VHOST_VRING_F_LOG` flag is set. Use
+    /// `DirtyLog::new(size)` to allocate a correctly sized bitmap.
+    ///
+    /// # Arguments
+    /// * `base` - Address of a bitmap large enough to log `size` bytes of guest memory.
+    /// * `size` - Size, in bytes, of the guest memory range covered by the bitmap.
+    fn set_log_base(&self, base: *const u8, size: u64) -> Result<()> {
+        debug_assert!(size > 0);
+        let log_base = base as u64;
+
+        // This ioctl is called on a valid vhost_net fd and has its
+        // return value checked.
+        let ret = unsafe { ioctl_with_ref(self, virtio_sys::VHOST_SET_LOG_BASE(), &log_base) };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+
     /// Set the eventfd to trigger when buffers have been used by the host.
     ///
     /// # Arguments
@@ -328,6 +395,66 @@ pub trait Vhost: AsRawFd + std::marker::Sized {
         Ok(())
     }
 
+    /// Set the eventfd the kernel backend signals when a vring enters an error state, letting
+    /// the device worker detect and react to backend failures instead of silently stalling.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - EventFd that the kernel will signal on error.
+    fn set_vring_err(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        let vring_file = virtio_sys::vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.as_raw_fd(),
+        };
+
+        // This ioctl is called on a valid vhost_net fd and has its
+        // return value checked.
+        let ret = unsafe {
+            ioctl_with_ref(self,
+                           virtio_sys::VHOST_SET_VRING_ERR(),
+                           &vring_file)
+        };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+
+    /// Set the CID to be assigned to the guest. This is the address by which
+    /// vsock packets are routed to the guest.
+    ///
+    /// # Arguments
+    /// * `cid` - CID to assign to the guest.
+    fn set_guest_cid(&self, cid: u64) -> Result<()> {
+        // This ioctl is called on a valid vhost_vsock fd and has its
+        // return value checked.
+        let ret = unsafe {
+            ioctl_with_ref(self, virtio_sys::VHOST_VSOCK_SET_GUEST_CID(), &cid)
+        };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+
+    /// Start or stop the vsock device, attaching or detaching it from the
+    /// vring processing loop.
+    ///
+    /// # Arguments
+    /// * `start` - Whether to start (`true`) or stop (`false`) the device.
+    fn set_running(&self, start: bool) -> Result<()> {
+        let on: i32 = if start { 1 } else { 0 };
+
+        // This ioctl is called on a valid vhost_vsock fd and has its
+        // return value checked.
+        let ret = unsafe {
+            ioctl_with_ref(self, virtio_sys::VHOST_VSOCK_SET_RUNNING(), &on)
+        };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -369,4 +496,119 @@ mod tests {
         let vhost_net = Net::new(&gm).unwrap();
         vhost_net.set_features(0).unwrap();
     }
+
+    #[test]
+    fn open_vhostnet_multiqueue() {
+        let gm = create_guest_memory().unwrap();
+        let net = MultiQueueNet::new(&gm, 4).unwrap();
+        assert_eq!(net.queue_pairs(), 4);
+
+        // Each queue pair owns its own vhost-net fd, so each must be set up (and can be torn
+        // down) independently of the others.
+        for i in 0..net.queue_pairs() {
+            let vhost_net = net.queue(i);
+            vhost_net.set_owner().unwrap();
+            vhost_net.set_vring_num(0, 256).unwrap();
+            vhost_net.set_vring_num(1, 256).unwrap();
+
+            let tap_fd = EventFd::new().unwrap();
+            vhost_net.set_backend(0, &tap_fd).unwrap();
+            vhost_net.set_backend(1, &tap_fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn set_log_base() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_net = Net::new(&gm).unwrap();
+        let mem_size = gm.end_addr().offset() as u64;
+        let log = DirtyLog::new(mem_size);
+        vhost_net.set_log_base(log.as_ptr(), mem_size).unwrap();
+    }
+
+    #[test]
+    fn set_vring_err() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_net = Net::new(&gm).unwrap();
+        let err_evt = EventFd::new().unwrap();
+        vhost_net.set_vring_err(0, &err_evt).unwrap();
+    }
+
+    #[test]
+    fn vring_addr_valid_packed() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_net = Net::new(&gm).unwrap();
+        let queue_size = 4;
+
+        // A packed ring only needs the 16-byte-per-entry descriptor ring plus a 4-byte
+        // driver/device event-suppression structure each, unlike a split ring's separate
+        // available/used rings.
+        assert!(vhost_net.is_valid(RingLayout::Packed,
+                                    queue_size,
+                                    queue_size,
+                                    GuestAddress(0x0),
+                                    GuestAddress(0x100),
+                                    GuestAddress(0x104)));
+    }
+
+    #[test]
+    fn vring_addr_invalid_packed_out_of_range() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_net = Net::new(&gm).unwrap();
+        let queue_size = 4;
+
+        // The descriptor ring for queue_size 4 needs 64 bytes; placing it one byte from the
+        // end of guest memory runs it past the end of the address space.
+        let end_addr = gm.end_addr().offset();
+        assert!(!vhost_net.is_valid(RingLayout::Packed,
+                                     queue_size,
+                                     queue_size,
+                                     GuestAddress(end_addr - 1),
+                                     GuestAddress(0x100),
+                                     GuestAddress(0x104)));
+    }
+
+    #[test]
+    fn vring_addr_valid_packed_non_power_of_two_size() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_net = Net::new(&gm).unwrap();
+
+        // Unlike a split ring, a packed ring doesn't need its queue size to be a power of two.
+        let queue_size = 3;
+        assert!(vhost_net.is_valid(RingLayout::Packed,
+                                    queue_size,
+                                    queue_size,
+                                    GuestAddress(0x0),
+                                    GuestAddress(0x100),
+                                    GuestAddress(0x104)));
+
+        // But a split ring does.
+        assert!(!vhost_net.is_valid(RingLayout::Split,
+                                     queue_size,
+                                     queue_size,
+                                     GuestAddress(0x0),
+                                     GuestAddress(0x100),
+                                     GuestAddress(0x104)));
+    }
+
+    #[test]
+    fn open_vhostvsock() {
+        let gm = create_guest_memory().unwrap();
+        Vsock::new(&gm).unwrap();
+    }
+
+    #[test]
+    fn set_guest_cid() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_vsock = Vsock::new(&gm).unwrap();
+        vhost_vsock.set_guest_cid(3).unwrap();
+    }
+
+    #[test]
+    fn set_running() {
+        let gm = create_guest_memory().unwrap();
+        let vhost_vsock = Vsock::new(&gm).unwrap();
+        vhost_vsock.set_running(true).unwrap();
+        vhost_vsock.set_running(false).unwrap();
+    }
 }
\ No newline at end of file
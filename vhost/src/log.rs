@@ -0,0 +1,99 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Support for the vhost dirty-page log used to track guest memory writes during live
+//! migration. Once `VHOST_F_LOG_ALL` is negotiated and a vring's `VHOST_VRING_F_LOG` flag is
+//! set, the kernel backend marks one bit per dirtied `VHOST_LOG_PAGE` region of guest physical
+//! memory into the bitmap handed to `Vhost::set_log_base`.
+
+/// Size in bytes of the guest physical memory region covered by a single bit in the log.
+pub const VHOST_LOG_PAGE: u64 = 0x1000;
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// A bitmap of dirtied guest memory pages, sized to cover a guest memory region and written to
+/// by the kernel vhost backend. The kernel sets bits concurrently with `read_and_clear` while
+/// the guest keeps running during live migration, so each byte is an `AtomicU8` rather than a
+/// plain `u8`: reading and clearing it must happen as one indivisible step, or a bit the kernel
+/// sets between our read and our clear would be silently dropped.
+pub struct DirtyLog {
+    bitmap: Vec<AtomicU8>,
+}
+
+impl DirtyLog {
+    /// Creates a bitmap large enough to hold one bit per `VHOST_LOG_PAGE` bytes of a `mem_size`
+    /// byte guest memory.
+    pub fn new(mem_size: u64) -> DirtyLog {
+        let num_pages = (mem_size + VHOST_LOG_PAGE - 1) / VHOST_LOG_PAGE;
+        let num_bytes = (num_pages + 7) / 8;
+        let mut bitmap = Vec::with_capacity(num_bytes as usize);
+        bitmap.resize_with(num_bytes as usize, AtomicU8::default);
+        DirtyLog { bitmap: bitmap }
+    }
+
+    /// Address of the backing bitmap, to be passed as the `base` argument of
+    /// `Vhost::set_log_base`.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.bitmap.as_ptr() as *const u8
+    }
+
+    /// Size in bytes of the backing bitmap.
+    pub fn len(&self) -> usize {
+        self.bitmap.len()
+    }
+
+    /// Returns the guest physical page numbers dirtied since the log was last cleared, and
+    /// clears their bits so the next call only reports newly dirtied pages.
+    pub fn read_and_clear(&mut self) -> Vec<u64> {
+        let mut pages = Vec::new();
+        for (byte_index, byte) in self.bitmap.iter().enumerate() {
+            // Atomically take the byte's value and zero it in one step, so a bit the kernel
+            // sets between the load and the clear is never lost: it either lands before this
+            // swap (and we observe and clear it now) or after it (and we'll observe it next
+            // time).
+            let value = byte.fetch_and(0, Ordering::AcqRel);
+            if value == 0 {
+                continue;
+            }
+            for bit in 0..8 {
+                if value & (1 << bit) != 0 {
+                    pages.push(byte_index as u64 * 8 + bit as u64);
+                }
+            }
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_size() {
+        assert_eq!(DirtyLog::new(0).len(), 0);
+        assert_eq!(DirtyLog::new(1).len(), 1);
+        assert_eq!(DirtyLog::new(VHOST_LOG_PAGE * 8).len(), 1);
+        assert_eq!(DirtyLog::new(VHOST_LOG_PAGE * 8 + 1).len(), 2);
+    }
+
+    #[test]
+    fn read_and_clear() {
+        let mut log = DirtyLog::new(VHOST_LOG_PAGE * 16);
+        log.bitmap[0].store(0b0000_0101, Ordering::Relaxed);
+        log.bitmap[1].store(0b0000_0001, Ordering::Relaxed);
+        let mut dirty = log.read_and_clear();
+        dirty.sort();
+        assert_eq!(dirty, vec![0, 2, 8]);
+        assert_eq!(log.read_and_clear(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn read_and_clear_leaves_bitmap_zeroed() {
+        let mut log = DirtyLog::new(VHOST_LOG_PAGE * 8);
+        log.bitmap[0].fetch_or(0b0000_0001, Ordering::AcqRel);
+        assert_eq!(log.read_and_clear(), vec![0]);
+        assert_eq!(log.bitmap[0].load(Ordering::Relaxed), 0);
+    }
+}
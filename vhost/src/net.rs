@@ -0,0 +1,113 @@
+// Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+use sys_util::{ioctl_with_ref, GuestMemory};
+use virtio_sys;
+
+use super::{ioctl_result, Error, Result, Vhost};
+
+static VHOST_NET_PATH: &'static str = "/dev/vhost-net";
+
+/// Feature bit advertised when the device was configured with more than one RX/TX vring pair,
+/// letting the guest spread traffic across queues instead of serializing it on a single vring.
+pub const VIRTIO_NET_F_MQ: u64 = 1 << 22;
+
+/// Handle for running VHOST_NET ioctls. Each `Net` owns a single `/dev/vhost-net` fd, which the
+/// kernel ABI limits to a single RX/TX vring pair (vring index 0 = RX, 1 = TX); see
+/// `MultiQueueNet` for `VIRTIO_NET_F_MQ` support.
+pub struct Net {
+    // fd must be the first field for the Drop implementation.
+    fd: File,
+    mem: GuestMemory,
+}
+
+impl Net {
+    /// Opens /dev/vhost-net and holds a file descriptor open for it, configured for a single
+    /// RX/TX vring pair.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest memory mapping.
+    pub fn new(mem: &GuestMemory) -> Result<Net> {
+        Ok(Net {
+            fd: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+                .open(VHOST_NET_PATH)
+                .map_err(Error::VhostOpen)?,
+            mem: mem.clone(),
+        })
+    }
+
+    /// Sets the tap file descriptor that will serve as the VHOST_NET backend for the vring at
+    /// `queue_index`. This starts the vhost worker for that queue.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - Tap interface fd.
+    pub fn set_backend(&self, queue_index: usize, fd: &AsRawFd) -> Result<()> {
+        let vring_file = virtio_sys::vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.as_raw_fd(),
+        };
+
+        // This ioctl is called on a valid vhost_net fd and has its
+        // return value checked.
+        let ret = unsafe { ioctl_with_ref(self, virtio_sys::VHOST_NET_SET_BACKEND(), &vring_file) };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Net {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Vhost for Net {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+}
+
+/// A `VIRTIO_NET_F_MQ` device's RX/TX vring pairs. `/dev/vhost-net`'s kernel ABI only exposes a
+/// single vring pair per open fd, so supporting more than one queue pair means opening one `Net`
+/// per pair rather than sharing a single fd across a queue-pair counter.
+pub struct MultiQueueNet {
+    queues: Vec<Net>,
+}
+
+impl MultiQueueNet {
+    /// Opens one `/dev/vhost-net` fd per queue pair, so the guest can negotiate
+    /// `VIRTIO_NET_F_MQ` and spread traffic across queues on multi-vCPU guests.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest memory mapping.
+    /// * `queue_pairs` - Number of RX/TX vring pairs to support.
+    pub fn new(mem: &GuestMemory, queue_pairs: usize) -> Result<MultiQueueNet> {
+        let queues = (0..queue_pairs)
+            .map(|_| Net::new(mem))
+            .collect::<Result<Vec<Net>>>()?;
+        Ok(MultiQueueNet { queues: queues })
+    }
+
+    /// Number of RX/TX vring pairs this device was configured for.
+    pub fn queue_pairs(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// The `Net` handle backing queue pair `queue_pair_index`.
+    pub fn queue(&self, queue_pair_index: usize) -> &Net {
+        &self.queues[queue_pair_index]
+    }
+}
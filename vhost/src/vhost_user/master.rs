@@ -0,0 +1,506 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use libc;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr::null_mut;
+
+use sys_util::EventFd;
+
+use super::message::{VhostUserInflight, VhostUserMemory, VhostUserMemoryRegion,
+                      VhostUserMsgHeader, VhostUserRequest, VhostUserU64, VhostUserVringAddr,
+                      VhostUserVringFile, VhostUserVringState, VHOST_USER_REPLY_MASK,
+                      VHOST_USER_VRING_NOFD};
+use {Error, Result};
+
+/// A shared memory region tracking which descriptors a vhost-user slave has taken from a
+/// vring's avail ring but not yet returned on its used ring, obtained via
+/// `Master::get_inflight_fd` and handed back via `Master::set_inflight_fd` after the slave
+/// reconnects, so it can replay or complete those descriptors without guest-visible I/O
+/// corruption.
+pub struct InflightRegion {
+    fd: File,
+    mmap_size: u64,
+    mmap_offset: u64,
+    num_queues: u16,
+    queue_size: u16,
+}
+
+impl InflightRegion {
+    /// File descriptor backing the shared memory region.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Size, in bytes, of the mapped region.
+    pub fn mmap_size(&self) -> u64 {
+        self.mmap_size
+    }
+
+    /// Offset, in bytes, of the region within the file referred to by `as_raw_fd`.
+    pub fn mmap_offset(&self) -> u64 {
+        self.mmap_offset
+    }
+}
+
+// Maximum number of file descriptors that can be attached to a single vhost-user message as
+// SCM_RIGHTS ancillary data (one per guest memory region for SET_MEM_TABLE, the largest user).
+const MAX_ATTACHED_FDS: usize = 8;
+
+/// One region of guest memory to describe to the slave via `Master::set_mem_table`. Unlike the
+/// in-kernel `Vhost` trait's `set_mem_table` (see `vhost::Vhost`), which only needs the
+/// already-mapped `userspace_addr` that `GuestMemory::with_regions_mut` hands out, a vhost-user
+/// slave runs in its own process and must `mmap` the region itself, so it additionally needs the
+/// file descriptor backing it and the region's offset within that file.
+pub struct VhostUserMemoryRegionInfo {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_fd: RawFd,
+    pub mmap_offset: u64,
+}
+
+/// A connection to a vhost-user slave process. `Master` sends the same logical operations as
+/// the in-kernel `Vhost` trait, but as messages over a Unix domain socket instead of ioctls,
+/// letting the device backend run in a separate, sandboxable process.
+pub struct Master {
+    sock: UnixStream,
+}
+
+impl Master {
+    /// Connects to a vhost-user slave listening on the Unix domain socket at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the slave's listening socket.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Master> {
+        let sock = UnixStream::connect(path).map_err(Error::VhostUserConnect)?;
+        Ok(Master { sock: sock })
+    }
+
+    /// Sets the current process as the owner of the vhost-user connection. Must be called
+    /// before any other request.
+    pub fn set_owner(&self) -> Result<()> {
+        self.send_request(VhostUserRequest::SetOwner, &[])
+    }
+
+    /// Gets the bitmask of virtio/vhost features the slave supports.
+    pub fn get_features(&self) -> Result<u64> {
+        self.send_request(VhostUserRequest::GetFeatures, &[])?;
+        self.recv_u64_reply(VhostUserRequest::GetFeatures)
+    }
+
+    /// Tells the slave which features to enable. Should be a subset of `get_features`.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        let body = VhostUserU64 { value: features };
+        self.send_request(VhostUserRequest::SetFeatures, as_bytes(&body))
+    }
+
+    /// Gets the bitmask of vhost-user protocol extensions the slave supports.
+    pub fn get_protocol_features(&self) -> Result<u64> {
+        self.send_request(VhostUserRequest::GetProtocolFeatures, &[])?;
+        self.recv_u64_reply(VhostUserRequest::GetProtocolFeatures)
+    }
+
+    /// Tells the slave which protocol extensions to enable.
+    pub fn set_protocol_features(&self, features: u64) -> Result<()> {
+        let body = VhostUserU64 { value: features };
+        self.send_request(VhostUserRequest::SetProtocolFeatures, as_bytes(&body))
+    }
+
+    /// Describes the guest memory layout to the slave, passing each region's backing file
+    /// descriptor as `SCM_RIGHTS` ancillary data.
+    ///
+    /// # Arguments
+    /// * `regions` - One entry per guest memory region, along with the file descriptor and
+    ///   offset the slave should `mmap` to access it.
+    pub fn set_mem_table(&self, regions: &[VhostUserMemoryRegionInfo]) -> Result<()> {
+        if regions.len() > MAX_ATTACHED_FDS {
+            return Err(Error::VhostUserProtocol(io::Error::new(io::ErrorKind::InvalidInput,
+                                                                "too many memory regions")));
+        }
+
+        let header = VhostUserMemory {
+            num_regions: regions.len() as u32,
+            padding: 0,
+        };
+
+        let mut body = as_bytes(&header).to_vec();
+        let mut fds = Vec::with_capacity(regions.len());
+        for region in regions {
+            let wire_region = VhostUserMemoryRegion {
+                guest_phys_addr: region.guest_phys_addr,
+                memory_size: region.memory_size,
+                user_addr: region.userspace_addr,
+                mmap_offset: region.mmap_offset,
+            };
+            body.extend_from_slice(as_bytes(&wire_region));
+            fds.push(region.mmap_fd);
+        }
+
+        self.send_request_with_fds(VhostUserRequest::SetMemTable, &body, &fds)
+    }
+
+    /// Sets the number of descriptors in a vring.
+    pub fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
+        let body = VhostUserVringState {
+            index: queue_index as u32,
+            num: num as u32,
+        };
+        self.send_request(VhostUserRequest::SetVringNum, as_bytes(&body))
+    }
+
+    /// Sets the addresses of a vring's descriptor table, used ring and available ring.
+    pub fn set_vring_addr(&self,
+                           queue_index: usize,
+                           flags: u32,
+                           desc_user_addr: u64,
+                           used_user_addr: u64,
+                           avail_user_addr: u64,
+                           log_guest_addr: u64)
+                           -> Result<()> {
+        let body = VhostUserVringAddr {
+            index: queue_index as u32,
+            flags: flags,
+            desc_user_addr: desc_user_addr,
+            used_user_addr: used_user_addr,
+            avail_user_addr: avail_user_addr,
+            log_guest_addr: log_guest_addr,
+        };
+        self.send_request(VhostUserRequest::SetVringAddr, as_bytes(&body))
+    }
+
+    /// Sets the first index to look for available descriptors.
+    pub fn set_vring_base(&self, queue_index: usize, num: u16) -> Result<()> {
+        let body = VhostUserVringState {
+            index: queue_index as u32,
+            num: num as u32,
+        };
+        self.send_request(VhostUserRequest::SetVringBase, as_bytes(&body))
+    }
+
+    /// Sets the eventfd the slave should signal when buffers have been used.
+    pub fn set_vring_call(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_fd(VhostUserRequest::SetVringCall, queue_index, fd)
+    }
+
+    /// Sets the eventfd the master will signal when buffers are made available by the guest.
+    pub fn set_vring_kick(&self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_fd(VhostUserRequest::SetVringKick, queue_index, fd)
+    }
+
+    /// Enables or disables a vring. Vrings must be explicitly enabled after being configured,
+    /// once protocol feature `VHOST_USER_F_PROTOCOL_FEATURES` has been negotiated.
+    pub fn set_vring_enable(&self, queue_index: usize, enable: bool) -> Result<()> {
+        let body = VhostUserVringState {
+            index: queue_index as u32,
+            num: enable as u32,
+        };
+        self.send_request(VhostUserRequest::SetVringEnable, as_bytes(&body))
+    }
+
+    /// Negotiates a shared memory region describing the in-flight descriptors of `num_queues`
+    /// vrings of `queue_size` entries each, so a crash-resilient backend can recover the state
+    /// it needs to replay or complete descriptors taken before a restart.
+    pub fn get_inflight_fd(&self, num_queues: u16, queue_size: u16) -> Result<InflightRegion> {
+        let request_body = VhostUserInflight {
+            mmap_size: 0,
+            mmap_offset: 0,
+            num_queues: num_queues,
+            queue_size: queue_size,
+        };
+        self.send_request(VhostUserRequest::GetInflightFd, as_bytes(&request_body))?;
+
+        let mut header = VhostUserMsgHeader::default();
+        recv_exact(&self.sock, as_bytes_mut(&mut header)).map_err(Error::VhostUserProtocol)?;
+        check_reply_header::<VhostUserInflight>(&header, VhostUserRequest::GetInflightFd)?;
+        let mut body = VhostUserInflight::default();
+        let fd = recv_with_fd(&self.sock, as_bytes_mut(&mut body))
+            .map_err(Error::VhostUserProtocol)?
+            .ok_or_else(|| {
+                Error::VhostUserProtocol(io::Error::new(io::ErrorKind::InvalidData,
+                                                         "missing inflight region fd"))
+            })?;
+        // Safe because `fd` was just received as a SCM_RIGHTS ancillary fd, giving us sole
+        // ownership of it, and no other code holds or closes it.
+        let fd = unsafe { File::from_raw_fd(fd) };
+
+        Ok(InflightRegion {
+            fd: fd,
+            mmap_size: body.mmap_size,
+            mmap_offset: body.mmap_offset,
+            num_queues: body.num_queues,
+            queue_size: body.queue_size,
+        })
+    }
+
+    /// Hands a previously obtained inflight region back to the slave, e.g. after it has
+    /// reconnected following a restart.
+    pub fn set_inflight_fd(&self, region: &InflightRegion) -> Result<()> {
+        let body = VhostUserInflight {
+            mmap_size: region.mmap_size,
+            mmap_offset: region.mmap_offset,
+            num_queues: region.num_queues,
+            queue_size: region.queue_size,
+        };
+        self.send_request_with_fds(VhostUserRequest::SetInflightFd,
+                                    as_bytes(&body),
+                                    &[region.as_raw_fd()])
+    }
+
+    fn set_vring_fd(&self,
+                     request: VhostUserRequest,
+                     queue_index: usize,
+                     fd: &EventFd)
+                     -> Result<()> {
+        let body = VhostUserVringFile { index: queue_index as u32 };
+        self.send_request_with_fds(request, as_bytes(&body), &[fd.as_raw_fd()])
+    }
+
+    fn send_request(&self, request: VhostUserRequest, body: &[u8]) -> Result<()> {
+        self.send_request_with_fds(request, body, &[])
+    }
+
+    fn send_request_with_fds(&self,
+                              request: VhostUserRequest,
+                              body: &[u8],
+                              fds: &[RawFd])
+                              -> Result<()> {
+        let header = VhostUserMsgHeader::new(request, body.len() as u32);
+        let mut msg = as_bytes(&header).to_vec();
+        msg.extend_from_slice(body);
+        send_with_fds(&self.sock, &msg, fds).map_err(Error::VhostUserProtocol)
+    }
+
+    fn recv_u64_reply(&self, request: VhostUserRequest) -> Result<u64> {
+        let mut header = VhostUserMsgHeader::default();
+        recv_exact(&self.sock, as_bytes_mut(&mut header)).map_err(Error::VhostUserProtocol)?;
+        check_reply_header::<VhostUserU64>(&header, request)?;
+        let mut body = VhostUserU64::default();
+        recv_exact(&self.sock, as_bytes_mut(&mut body)).map_err(Error::VhostUserProtocol)?;
+        Ok(body.value)
+    }
+}
+
+// Checks that `header`, just received in reply to `request`, is actually a well-formed reply to
+// it: carries the reply flag, echoes back the same request, and declares a body the size of
+// `Body`. Without this, a slave that replies out of order, with an error, or to a different
+// request than expected would be silently misinterpreted as a valid `Body` instead of surfaced
+// as a protocol error.
+fn check_reply_header<Body>(header: &VhostUserMsgHeader, request: VhostUserRequest) -> Result<()> {
+    if header.flags & VHOST_USER_REPLY_MASK == 0 {
+        return Err(Error::VhostUserProtocol(io::Error::new(io::ErrorKind::InvalidData,
+                                                             "reply flag not set")));
+    }
+    if header.request != request as u32 {
+        return Err(Error::VhostUserProtocol(io::Error::new(io::ErrorKind::InvalidData,
+                                                             "reply to unexpected request")));
+    }
+    if header.size as usize != mem::size_of::<Body>() {
+        return Err(Error::VhostUserProtocol(io::Error::new(io::ErrorKind::InvalidData,
+                                                             "unexpected reply body size")));
+    }
+    Ok(())
+}
+
+impl AsRawFd for Master {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+// Safe because all of the above message types are repr(C), contain only plain-old-data fields
+// and have no padding-sensitive invariants, so viewing them as a byte slice is always valid.
+fn as_bytes<T>(val: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(val: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(val as *mut T as *mut u8, mem::size_of::<T>()) }
+}
+
+fn recv_exact(sock: &UnixStream, mut buf: &mut [u8]) -> io::Result<()> {
+    use std::io::Read;
+    (&*sock).read_exact(&mut buf)
+}
+
+// Reads exactly `buf.len()` bytes from `sock` into `buf`, returning the first file descriptor
+// attached as SCM_RIGHTS ancillary data, if any.
+fn recv_with_fd(sock: &UnixStream, buf: &mut [u8]) -> io::Result<Option<RawFd>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut cmsg_buf = [0u8; 64];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len();
+
+    // Safe because `msg` describes a single valid iovec sized to `buf` and a control buffer
+    // large enough for one attached fd, both of which outlive this call.
+    let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if (ret as usize) < buf.len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short vhost-user message"));
+    }
+
+    if msg.msg_controllen == 0 {
+        return Ok(None);
+    }
+
+    // Safe because `msg_controllen` is non-zero, meaning the kernel filled in at least one
+    // cmsghdr within `cmsg_buf`.
+    let cmsg: &libc::cmsghdr = unsafe { &*(cmsg_buf.as_ptr() as *const libc::cmsghdr) };
+    if cmsg.cmsg_level != libc::SOL_SOCKET || cmsg.cmsg_type != libc::SCM_RIGHTS {
+        return Ok(None);
+    }
+    let data = unsafe { libc::CMSG_DATA(cmsg) as *const RawFd };
+    Ok(Some(unsafe { *data }))
+}
+
+// Sends `buf` over `sock`, attaching `fds` as SCM_RIGHTS ancillary data so the slave process
+// receives working file descriptors into its own descriptor table.
+fn send_with_fds(sock: &UnixStream, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_ATTACHED_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many fds to attach"));
+    }
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut cmsg_buf = [0u8; 64];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !fds.is_empty() {
+        let cmsg_len = unsafe { libc::CMSG_SPACE((fds.len() * mem::size_of::<RawFd>()) as u32) };
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_len as usize;
+
+        let cmsg: &mut libc::cmsghdr = unsafe { &mut *(cmsg_buf.as_mut_ptr() as *mut libc::cmsghdr) };
+        cmsg.cmsg_len = unsafe { libc::CMSG_LEN((fds.len() * mem::size_of::<RawFd>()) as u32) as usize };
+        cmsg.cmsg_level = libc::SOL_SOCKET;
+        cmsg.cmsg_type = libc::SCM_RIGHTS;
+
+        let data = unsafe { libc::CMSG_DATA(cmsg) as *mut RawFd };
+        for (i, fd) in fds.iter().enumerate() {
+            unsafe { *data.offset(i as isize) = *fd };
+        }
+    } else {
+        msg.msg_control = null_mut();
+        msg.msg_controllen = 0;
+    }
+
+    // Safe because `msg` describes a single valid iovec and, when present, a correctly sized
+    // SCM_RIGHTS control message backed by `cmsg_buf`, both of which outlive this call.
+    let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Returns the inode backing `fd`, so a test can check that two fds refer to the same open file
+// description without taking ownership of either.
+fn fd_ino(fd: RawFd) -> libc::ino_t {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::fstat(fd, &mut stat) };
+    assert_eq!(ret, 0);
+    stat.st_ino
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_and_recv_fd_round_trip() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        // An arbitrary fd to pass across the socket; its peer just keeps it from being closed
+        // out from under us.
+        let (passed, _peer) = UnixStream::pair().unwrap();
+        let body = [0x12u8, 0x34, 0x56, 0x78];
+
+        send_with_fds(&tx, &body, &[passed.as_raw_fd()]).unwrap();
+
+        let mut received_body = [0u8; 4];
+        let received_fd = recv_with_fd(&rx, &mut received_body)
+            .unwrap()
+            .expect("fd should have been attached");
+
+        assert_eq!(received_body, body);
+        assert_eq!(fd_ino(received_fd), fd_ino(passed.as_raw_fd()));
+
+        // Take ownership so the fd the kernel duplicated for us doesn't leak.
+        unsafe { File::from_raw_fd(received_fd) };
+    }
+
+    #[test]
+    fn check_reply_header_rejects_missing_reply_flag() {
+        let header = VhostUserMsgHeader {
+            request: VhostUserRequest::GetFeatures as u32,
+            flags: 0,
+            size: mem::size_of::<VhostUserU64>() as u32,
+        };
+        assert!(check_reply_header::<VhostUserU64>(&header, VhostUserRequest::GetFeatures)
+                    .is_err());
+    }
+
+    #[test]
+    fn check_reply_header_rejects_mismatched_request() {
+        let header = VhostUserMsgHeader {
+            request: VhostUserRequest::SetFeatures as u32,
+            flags: VHOST_USER_REPLY_MASK,
+            size: mem::size_of::<VhostUserU64>() as u32,
+        };
+        assert!(check_reply_header::<VhostUserU64>(&header, VhostUserRequest::GetFeatures)
+                    .is_err());
+    }
+
+    #[test]
+    fn check_reply_header_rejects_wrong_size() {
+        let header = VhostUserMsgHeader {
+            request: VhostUserRequest::GetFeatures as u32,
+            flags: VHOST_USER_REPLY_MASK,
+            size: 0,
+        };
+        assert!(check_reply_header::<VhostUserU64>(&header, VhostUserRequest::GetFeatures)
+                    .is_err());
+    }
+
+    #[test]
+    fn check_reply_header_accepts_well_formed_reply() {
+        let header = VhostUserMsgHeader {
+            request: VhostUserRequest::GetFeatures as u32,
+            flags: VHOST_USER_REPLY_MASK,
+            size: mem::size_of::<VhostUserU64>() as u32,
+        };
+        assert!(check_reply_header::<VhostUserU64>(&header, VhostUserRequest::GetFeatures)
+                    .is_ok());
+    }
+
+    #[test]
+    fn send_and_recv_without_fds() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let body = [0xaau8, 0xbb, 0xcc, 0xdd];
+
+        send_with_fds(&tx, &body, &[]).unwrap();
+
+        let mut received_body = [0u8; 4];
+        assert!(recv_with_fd(&rx, &mut received_body).unwrap().is_none());
+        assert_eq!(received_body, body);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Wire definitions for the vhost-user protocol: the messages a `Master` sends over the Unix
+//! domain socket in place of the in-kernel vhost ioctls used by the `Vhost` trait.
+
+/// Protocol version implemented by `Master`.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+
+/// Set in a message header's `flags` field to mark the protocol version in use.
+const VHOST_USER_VERSION_MASK: u32 = 0x3;
+/// Set in a message header's `flags` field on a slave's reply to a master request.
+pub const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+
+/// Requests a `Master` can send to a vhost-user slave, mirroring the ioctls of the in-kernel
+/// `Vhost` trait.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum VhostUserRequest {
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    SetMemTable = 5,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    SetVringEnable = 18,
+    GetInflightFd = 31,
+    SetInflightFd = 32,
+}
+
+/// Fixed-size header prepended to every vhost-user message.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserMsgHeader {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+impl VhostUserMsgHeader {
+    pub fn new(request: VhostUserRequest, size: u32) -> VhostUserMsgHeader {
+        VhostUserMsgHeader {
+            request: request as u32,
+            flags: VHOST_USER_VERSION & VHOST_USER_VERSION_MASK,
+            size: size,
+        }
+    }
+}
+
+/// Body of a `GET_FEATURES`/`SET_FEATURES`/`GET_PROTOCOL_FEATURES`/`SET_PROTOCOL_FEATURES`
+/// message: a single 64-bit value.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserU64 {
+    pub value: u64,
+}
+
+/// Header of a `SET_MEM_TABLE` message body, immediately followed by `num_regions` instances of
+/// `VhostUserMemoryRegion`. The file descriptor of each region is passed out-of-band as
+/// `SCM_RIGHTS` ancillary data, in the same order as the regions.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserMemory {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+/// One guest memory region, as described to a vhost-user slave.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserMemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub user_addr: u64,
+    pub mmap_offset: u64,
+}
+
+/// Body of a `SET_VRING_NUM`/`SET_VRING_BASE`/`SET_VRING_ENABLE` message.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserVringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+/// Body of a `SET_VRING_ADDR` message.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserVringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+/// Body of a `SET_VRING_KICK`/`SET_VRING_CALL` message. The low byte of `index` carries the
+/// queue index; `VHOST_USER_VRING_NOFD` is set in it when no eventfd is attached as ancillary
+/// data and polling should be used instead.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserVringFile {
+    pub index: u32,
+}
+
+/// Bit set in `VhostUserVringFile::index` when no file descriptor is attached to the message.
+pub const VHOST_USER_VRING_NOFD: u32 = 0x1 << 8;
+
+/// Body of a `GET_INFLIGHT_FD`/`SET_INFLIGHT_FD` message, describing a shared memory region
+/// recording which descriptors have been taken from a vring's avail ring but not yet returned
+/// on its used ring. On `GET_INFLIGHT_FD` the slave fills in `mmap_size`/`mmap_offset` and
+/// attaches the backing fd as ancillary data; on `SET_INFLIGHT_FD` the master hands a
+/// previously obtained region back so a reconnected slave can replay or complete in-flight
+/// descriptors.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VhostUserInflight {
+    pub mmap_size: u64,
+    pub mmap_offset: u64,
+    pub num_queues: u16,
+    pub queue_size: u16,
+}
@@ -0,0 +1,12 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! vhost-user: a userspace implementation of the vhost protocol, carried over a Unix domain
+//! socket instead of ioctls on an in-kernel vhost character device. This lets a device backend
+//! (net, block, etc.) run in a separate, sandboxable process from the VMM.
+
+mod master;
+pub use self::master::Master;
+
+pub mod message;
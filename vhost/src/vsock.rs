@@ -0,0 +1,51 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use sys_util::GuestMemory;
+
+use super::{Error, Result, Vhost};
+
+static VHOST_VSOCK_PATH: &'static str = "/dev/vhost-vsock";
+
+/// Handle for running VHOST_VSOCK ioctls.
+pub struct Vsock {
+    // fd must be the first field for the Drop implementation.
+    fd: File,
+    mem: GuestMemory,
+}
+
+impl Vsock {
+    /// Opens /dev/vhost-vsock and holds a file descriptor open for it.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest memory mapping.
+    pub fn new(mem: &GuestMemory) -> Result<Vsock> {
+        Ok(Vsock {
+            fd: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+                .open(VHOST_VSOCK_PATH)
+                .map_err(Error::VhostOpen)?,
+            mem: mem.clone(),
+        })
+    }
+}
+
+impl AsRawFd for Vsock {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Vhost for Vsock {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+}